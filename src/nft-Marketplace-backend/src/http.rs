@@ -13,7 +13,9 @@ use serde::{Deserialize, Serialize};
 use serde_cbor::Serializer;
 use sha2::{Digest, Sha256};
 
-use crate::{MetadataPurpose, MetadataVal, STATE, NFT};
+use candid::Nat;
+
+use crate::{MetadataPurpose, MetadataVal, TokenId, STATE};
 
 #[derive(CandidType, Deserialize)]
 struct HttpRequest {
@@ -69,19 +71,22 @@ fn http_request(/* req: HttpRequest */) /* -> HttpResponse */
         let body;
         let mut code = 200;
         if root == "" {
-            body = format!("Total NFTs: {}", state.nfts.len())
+            body = format!("Total NFTs: {}", state.token_metadata.len())
                 .into_bytes()
                 .into();
         } else {
-            if let Ok(num) = root.parse::<usize>() {
-                if let Some(nft) = state.nfts.get(&num) {
+            if let Ok(num) = root.parse::<u64>() {
+                if state.burned.contains_key(&Nat::from(num)) {
+                    code = 404;
+                    body = b"No such NFT"[..].into();
+                } else if let Some(meta) = state.token_metadata.get(&Nat::from(num)) {
                     let img = path.next().unwrap_or_else(|| "".into());
                     if img == "" {
-                        let part = nft
-                            .metadata
+                        let part = meta
+                            .parts
                             .iter()
                             .find(|x| x.purpose == MetadataPurpose::Rendered)
-                            .or_else(|| nft.metadata.get(0));
+                            .or_else(|| meta.parts.first());
                         if let Some(part) = part {
                             // default metadata: first non-preview metadata, or if there is none, first metadata
                             body = part.data.as_slice().into();
@@ -95,7 +100,7 @@ fn http_request(/* req: HttpRequest */) /* -> HttpResponse */
                         }
                     } else {
                         if let Ok(num) = img.parse::<usize>() {
-                            if let Some(part) = nft.metadata.get(num) {
+                            if let Some(part) = meta.parts.get(num) {
                                 body = part.data.as_slice().into();
                                 if let Some(MetadataVal::TextContent(mime)) =
                                     part.key_val_data.get("contentType")
@@ -128,24 +133,57 @@ fn http_request(/* req: HttpRequest */) /* -> HttpResponse */
     });
 }
 
-pub fn add_hash(tkid: u64) {
+pub fn add_hash(token_id: TokenId) {
+    // Key the tree on the full decimal id so ids >= 2^64 cannot collide on the
+    // `/0` path; this matches the decimal form `http_request` parses back out.
+    let tkid = token_id.0.to_string();
     STATE.with(|state| {
         HASHES.with(|hashes| {
             let state = state.borrow();
             let mut hashes = hashes.borrow_mut();
-            let nft = state.nfts.get(&(tkid as usize)).unwrap();
+            let meta = state.token_metadata.get(&token_id).unwrap();
             let mut default = false;
-            for (i, metadata) in nft.metadata.iter().enumerate() {
-                let hash = Sha256::digest(&metadata.data);
+            for (i, part) in meta.parts.iter().enumerate() {
+                let hash = Sha256::digest(&part.data);
                 hashes.insert(format!("/{}/{}", tkid, i), hash.into());
-                if !default && matches!(metadata.purpose, MetadataPurpose::Rendered) {
+                if !default && matches!(part.purpose, MetadataPurpose::Rendered) {
                     default = true;
                     hashes.insert(format!("/{}", tkid), hash.into());
                 }
             }
+            // With no Rendered part, `http_request` serves the first part at
+            // `/<id>`, so certify that one as the default instead.
+            if !default {
+                if let Some(part) = meta.parts.first() {
+                    hashes.insert(format!("/{}", tkid), Sha256::digest(&part.data).into());
+                }
+            }
+            hashes.insert(
+                "/".to_string(),
+                Sha256::digest(format!("Total NFTs: {}", state.token_metadata.len())).into(),
+            );
+            let cert = ic_certified_map::labeled_hash(b"http_assets", &hashes.root_hash());
+            api::set_certified_data(&cert);
+            Some(())
+        })
+    });
+}
+
+pub fn remove_hash(token_id: TokenId) {
+    let tkid = token_id.0.to_string();
+    STATE.with(|state| {
+        HASHES.with(|hashes| {
+            let state = state.borrow();
+            let mut hashes = hashes.borrow_mut();
+            if let Some(meta) = state.token_metadata.get(&token_id) {
+                for i in 0..meta.parts.len() {
+                    hashes.delete(format!("/{}/{}", tkid, i).as_bytes());
+                }
+            }
+            hashes.delete(format!("/{}", tkid).as_bytes());
             hashes.insert(
                 "/".to_string(),
-                Sha256::digest(format!("Total NFTs: {}", state.nfts.len())).into(),
+                Sha256::digest(format!("Total NFTs: {}", state.token_metadata.len())).into(),
             );
             let cert = ic_certified_map::labeled_hash(b"http_assets", &hashes.root_hash());
             api::set_certified_data(&cert);
@@ -154,6 +192,26 @@ pub fn add_hash(tkid: u64) {
     });
 }
 
+// Recompute the certified `/` root entry from the live metadata count and
+// refresh `set_certified_data`. `add_hash` does this as a side effect per
+// token, but an upgrade that restores state with zero surviving (all-burned)
+// tokens never calls it, leaving the root hash stuck on the thread-local
+// `"Total NFTs: 0"` default while `http_request` reports the real count.
+pub fn certify_root() {
+    STATE.with(|state| {
+        HASHES.with(|hashes| {
+            let state = state.borrow();
+            let mut hashes = hashes.borrow_mut();
+            hashes.insert(
+                "/".to_string(),
+                Sha256::digest(format!("Total NFTs: {}", state.token_metadata.len())).into(),
+            );
+            let cert = ic_certified_map::labeled_hash(b"http_assets", &hashes.root_hash());
+            api::set_certified_data(&cert);
+        })
+    });
+}
+
 fn witness(name: &str) -> String {
     HASHES.with(|hashes| {
         let hashes = hashes.borrow();
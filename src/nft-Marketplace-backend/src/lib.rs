@@ -10,9 +10,11 @@ use ic_cdk::api;
 use ic_cdk::api::caller;
 use ic_cdk::api::call;
 use std::borrow::Cow;
-use ic_cdk::{init, query, update};
+use ic_cdk::{init, post_upgrade, pre_upgrade, query, update};
 use serde::{Deserialize, Serialize};
 
+mod http;
+
 type TokenId = Nat;
 type AccountIdentifier = String; // Represented as a String for simplicity
 type Subaccount = [u8; 32];
@@ -33,18 +35,54 @@ pub enum Error {
     AlreadyListedForSale,
     CannotBuyOwnNFT,
     InsufficientFunds, // Placeholder for payment handling
+    PaymentFailed,
+    LedgerError(String),
+    TokenBurned,
     Other(String),
 }
 
 // Define a result type for canister operations
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+// Role a metadata part plays when a token is rendered over HTTP.
+#[derive(CandidType, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub enum MetadataPurpose {
+    Preview,
+    Rendered,
+}
+
+// A typed metadata value, mirroring the DIP-721 `MetadataVal` union.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub enum MetadataVal {
+    TextContent(String),
+    BlobContent(Vec<u8>),
+    NatContent(Nat),
+    Nat8Content(u8),
+    Nat16Content(u16),
+    Nat32Content(u32),
+    Nat64Content(u64),
+}
+
+// A single certifiable media part served by `http_request`.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct MetadataPart {
+    pub purpose: MetadataPurpose,
+    pub key_val_data: HashMap<String, MetadataVal>,
+    pub data: Vec<u8>,
+}
+
 // Define the structure for NFT metadata (you can customize this)
 #[derive(CandidType, serde::Deserialize, Serialize, Clone)]
 pub struct Metadata {
     pub name: String,
     pub description: String,
     pub media_url: String,
+    // Optional royalty paid out on every secondary sale: the recipient and the
+    // share in basis points (0–10000). Set at mint time and immutable after.
+    pub royalty: Option<(Principal, u16)>,
+    // Certifiable media parts; their bytes are hashed into the asset tree so
+    // `http_request` can serve them with a valid `IC-Certificate`.
+    pub parts: Vec<MetadataPart>,
     // Add other metadata fields as needed
 }
 
@@ -55,19 +93,44 @@ pub struct Listing {
     pub price: Nat, // Price in some unit (e.g., ICP tokens)
 }
 
+// A single operation in the canister's on-chain audit trail.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub enum TxKind {
+    Mint { token_id: TokenId, to: Principal },
+    Transfer { token_id: TokenId, from: Principal, to: Principal },
+    Approve { token_id: TokenId, approved: Principal },
+    ListItem { token_id: TokenId, price: Nat },
+    DelistItem { token_id: TokenId },
+    Buy { token_id: TokenId, from: Principal, to: Principal, price: Nat },
+    Burn { token_id: TokenId },
+}
+
+// An immutable, append-only history record so explorers can reconstruct
+// provenance without re-deriving it from current ownership state.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Transaction {
+    pub id: Nat,
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub operation: TxKind,
+}
+
 // Define the state of the canister
 #[derive(CandidType, Deserialize, Serialize)]
 pub struct State {
     pub name: String,
     pub symbol: String,
     pub owner: Option<Principal>,
+    pub custodians: HashSet<Principal>, // Principals allowed to mint/burn and manage the collection
     pub total_supply: Nat,
     pub tokens: HashMap<TokenId, Principal>, // Token ID to owner
-    pub token_approvals: HashMap<TokenId, Principal>, // Token ID to approved principal
-    pub operator_approvals: HashMap<Principal, HashSet<Principal>>, // Owner to set of approved operators
+    pub token_approvals: HashMap<TokenId, (Principal, Option<u64>)>, // Token ID to (approved, expiry ns)
+    pub operator_approvals: HashMap<Principal, HashMap<Principal, Option<u64>>>, // Owner to operator expiries
     pub token_metadata: HashMap<TokenId, Metadata>, // Token ID to metadata
     pub next_token_id: Nat,
     pub listings: HashMap<TokenId, Listing>, // Token ID to Listing information
+    pub transactions: Vec<Transaction>, // Append-only audit trail
+    pub burned: HashMap<TokenId, Principal>, // Burned token ID to prior owner, for un-burn
 }
 
 impl Default for State {
@@ -76,6 +139,7 @@ impl Default for State {
             name: String::from("MyNFT"),
             symbol: String::from("MNFT"),
             owner: Some(Principal::anonymous()), // Will be set in init
+            custodians: HashSet::new(),
             total_supply: Nat::from(0u32),
             tokens: HashMap::new(),
             token_approvals: HashMap::new(),
@@ -83,6 +147,8 @@ impl Default for State {
             token_metadata: HashMap::new(),
             next_token_id: Nat::from(1u32),
             listings: HashMap::new(),
+            transactions: Vec::new(),
+            burned: HashMap::new(),
         }
     }
 }
@@ -94,6 +160,7 @@ thread_local! {
 #[derive(CandidType, Deserialize)]
 struct InitArgs {
     owner: Option<Principal>,
+    custodians: Option<HashSet<Principal>>,
     name: String,
     symbol: String,
 }
@@ -103,14 +170,56 @@ struct InitArgs {
 fn init(args: InitArgs) {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        state.owner = Some(args
-            .owner
-            .unwrap_or_else(api::caller));
-        state.name = name();
-        state.symbol = symbol();  
+        let owner = args.owner.unwrap_or_else(api::caller);
+        state.owner = Some(owner);
+        state.name = args.name;
+        state.symbol = args.symbol;
+        // The owner is always a custodian; additional custodians may be seeded.
+        state.custodians = args.custodians.unwrap_or_default();
+        state.custodians.insert(owner);
     });
 }
 
+// Versioned wrapper around the persisted payload so future schema migrations
+// can branch on the variant instead of failing to decode.
+#[derive(CandidType, Deserialize, Serialize)]
+enum StableState {
+    V1(State),
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    STATE.with(|s| {
+        let state = std::mem::take(&mut *s.borrow_mut());
+        ic_cdk::storage::stable_save((StableState::V1(state),))
+            .expect("failed to save state to stable memory");
+    });
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    let (stable,): (StableState,) =
+        ic_cdk::storage::stable_restore().expect("failed to restore state from stable memory");
+    let StableState::V1(state) = stable;
+    // Skip burned tokens: their hashes were removed on burn and must stay out of
+    // the rebuilt tree so `http_request` keeps returning 404 for them.
+    let token_ids: Vec<TokenId> = state
+        .tokens
+        .keys()
+        .filter(|id| !state.burned.contains_key(*id))
+        .cloned()
+        .collect();
+    STATE.with(|s| *s.borrow_mut() = state);
+    // Rebuild the certified-asset tree so `http_request` keeps serving valid
+    // `IC-Certificate`s for every surviving token after the upgrade.
+    for token_id in token_ids {
+        http::add_hash(token_id);
+    }
+    // Always refresh the `/` root entry, even when no tokens survived, so its
+    // certified hash matches the count `http_request` reports.
+    http::certify_root();
+}
+
 #[derive(CandidType, Deserialize, Clone)]
 struct LogoResult {
     logo_type: Cow<'static, str>,
@@ -160,9 +269,24 @@ fn ownerOf(token_id: TokenId) -> Result<Principal> {
 fn transferFrom(from: Principal, to: Principal, token_id: TokenId) -> Result<()> {
     STATE.with(|s| {
         let mut state = s.borrow_mut();
+
+        if state.burned.contains_key(&token_id) {
+            return Err(Error::TokenBurned);
+        }
+
+        // Lazily drop expired grants so they neither authorize nor linger.
+        if let Some((_, expiry)) = state.token_approvals.get(&token_id) {
+            if is_expired(expiry) {
+                state.token_approvals.remove(&token_id);
+            }
+        }
+        if let Some(operators) = state.operator_approvals.get_mut(&from) {
+            operators.retain(|_, expiry| !is_expired(expiry));
+        }
+
         let owner = state.tokens.get(&token_id).ok_or(Error::InvalidTokenId)?;
 
-        if *owner != from && !isApprovedForAllInternal(&state, &from, &caller()) && state.token_approvals.get(&token_id) != Some(&caller()) {
+        if *owner != from || !is_owner_or_operator(&state, &caller(), &token_id)? {
             return Err(Error::Unauthorized);
         }
 
@@ -173,6 +297,7 @@ fn transferFrom(from: Principal, to: Principal, token_id: TokenId) -> Result<()>
         state.tokens.insert(token_id.clone(), to);
         state.token_approvals.remove(&token_id); // Clear any existing approval
         state.listings.remove(&token_id); // Remove from listings if transferred
+        record_tx(&mut state, TxKind::Transfer { token_id, from, to });
         Ok(())
     })
 }
@@ -186,16 +311,20 @@ fn safeTransferFrom(from: Principal, to: Principal, token_id: TokenId) -> Result
 }
 
 #[update(name = "approveDip721")]
-fn approve(approved: Principal, token_id: TokenId) -> Result<()> {
+fn approve(approved: Principal, token_id: TokenId, expires_at: Option<u64>) -> Result<()> {
     STATE.with(|s| {
         let mut state = s.borrow_mut();
-        let owner = state.tokens.get(&token_id).ok_or(Error::InvalidTokenId)?;
+        if state.burned.contains_key(&token_id) {
+            return Err(Error::TokenBurned);
+        }
+        let owner = *state.tokens.get(&token_id).ok_or(Error::InvalidTokenId)?;
 
-        if *owner != caller() && !state.operator_approvals.get(&owner).map_or(false, |operators| operators.contains(&caller())) {
+        if owner != caller() && !isApprovedForAllInternal(&state, &owner, &caller()) {
             return Err(Error::Unauthorized);
         }
 
-        state.token_approvals.insert(token_id, approved);
+        state.token_approvals.insert(token_id.clone(), (approved, expires_at));
+        record_tx(&mut state, TxKind::Approve { token_id, approved });
         Ok(())
     })
 }
@@ -203,16 +332,15 @@ fn approve(approved: Principal, token_id: TokenId) -> Result<()> {
 #[query(name = "getApprovedDip721")]
 fn getApproved(token_id: TokenId) -> Result<Principal> {
     STATE.with(|s| {
-        s.borrow()
-            .token_approvals
-            .get(&token_id)
-            .cloned()
-            .ok_or(Error::InvalidTokenId)
+        match s.borrow().token_approvals.get(&token_id) {
+            Some((approved, expiry)) if !is_expired(expiry) => Ok(*approved),
+            _ => Err(Error::InvalidTokenId),
+        }
     })
 }
 
 #[update(name = "setApprovalForAllDip721")]
-fn setApprovalForAll(operator: Principal, approved: bool) -> Result<()> {
+fn setApprovalForAll(operator: Principal, approved: bool, expires_at: Option<u64>) -> Result<()> {
     STATE.with(|s| {
         let mut state = s.borrow_mut();
         let owner = caller();
@@ -221,7 +349,7 @@ fn setApprovalForAll(operator: Principal, approved: bool) -> Result<()> {
         }
         let operators = state.operator_approvals.entry(owner).or_default();
         if approved {
-            operators.insert(operator);
+            operators.insert(operator, expires_at);
         } else {
             operators.remove(&operator);
         }
@@ -238,19 +366,176 @@ fn isApprovedForAllInternal(state: &State, owner: &Principal, operator: &Princip
     state
         .operator_approvals
         .get(owner)
-        .map_or(false, |operators| operators.contains(operator))
+        .and_then(|operators| operators.get(operator))
+        .map_or(false, |expiry| !is_expired(expiry))
+}
+
+// An approval carrying an expiry is considered absent once the deadline has
+// passed. `None` means the grant never expires.
+fn is_expired(expiry: &Option<u64>) -> bool {
+    matches!(expiry, Some(deadline) if *deadline <= api::time())
+}
+
+// Whether `principal` is a custodian authorized to mint, burn, un-burn and
+// manage the collection.
+fn is_custodian(state: &State, principal: &Principal) -> bool {
+    state.custodians.contains(principal)
+}
+
+// Whether `principal` may act on `token_id` as the holder of the token: its
+// owner, an approved operator of the owner, or the per-token approved principal.
+// Custodian authority deliberately does NOT grant transfer rights over other
+// users' tokens — callers that need custodian power (e.g. burn) check it on top.
+fn is_owner_or_operator(state: &State, principal: &Principal, token_id: &TokenId) -> Result<bool> {
+    let owner = state.tokens.get(token_id).ok_or(Error::InvalidTokenId)?;
+    let token_approved = state
+        .token_approvals
+        .get(token_id)
+        .map_or(false, |(approved, expiry)| approved == principal && !is_expired(expiry));
+    Ok(owner == principal
+        || isApprovedForAllInternal(state, owner, principal)
+        || token_approved)
+}
+
+// Append an entry to the audit trail, stamping it with the current time and
+// caller. Called from the token-level entry points (mint, transfer, approve,
+// list, delist, buy, burn); operator grants, custodian changes, collection
+// name/symbol changes and un-burns are not recorded.
+fn record_tx(state: &mut State, operation: TxKind) {
+    let id = Nat::from(state.transactions.len() as u64);
+    state.transactions.push(Transaction {
+        id,
+        timestamp: api::time(),
+        caller: caller(),
+        operation,
+    });
+}
+
+#[query(name = "custodiansDip721")]
+fn custodians() -> Vec<Principal> {
+    STATE.with(|s| s.borrow().custodians.iter().cloned().collect())
+}
+
+#[update(name = "setCustodianDip721")]
+fn setCustodian(principal: Principal, is_custodian: bool) -> Result<()> {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if !state.custodians.contains(&caller()) {
+            return Err(Error::Unauthorized);
+        }
+        if is_custodian {
+            state.custodians.insert(principal);
+        } else {
+            state.custodians.remove(&principal);
+        }
+        Ok(())
+    })
 }
 
 #[update(name = "mintDip721")]
 fn mint(to: Principal, metadata: Metadata) -> Result<TokenId> {
+    let token_id = STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if !is_custodian(&state, &caller()) {
+            return Err(Error::Unauthorized);
+        }
+        validate_metadata(&metadata)?;
+        Ok(mint_internal(&mut state, to, metadata))
+    })?;
+    // Register the new token with the certified-asset tree so `http_request`
+    // can serve it with a valid `IC-Certificate`. Done outside the borrow above
+    // because `add_hash` re-borrows `STATE`.
+    http::add_hash(token_id.clone());
+    Ok(token_id)
+}
+
+// Reject metadata whose royalty share is outside the DIP-721 basis-point range.
+fn validate_metadata(metadata: &Metadata) -> Result<()> {
+    if let Some((_, bps)) = metadata.royalty {
+        if bps > 10_000 {
+            return Err(Error::Other(String::from(
+                "royalty bps must be between 0 and 10000",
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Insert a freshly minted token, returning its id. Certified-asset
+// registration is performed by the caller once the state borrow is released.
+fn mint_internal(state: &mut State, to: Principal, metadata: Metadata) -> TokenId {
+    let token_id = state.next_token_id.clone();
+    state.tokens.insert(token_id.clone(), to);
+    state.token_metadata.insert(token_id.clone(), metadata);
+    state.total_supply = state.total_supply.clone() + Nat::from(1u32);
+    state.next_token_id = state.next_token_id.clone() + Nat::from(1u32);
+    record_tx(state, TxKind::Mint { token_id: token_id.clone(), to });
+    token_id
+}
+
+#[update(name = "mintBatchDip721")]
+fn mintBatch(to: Principal, metadata: Vec<Metadata>) -> Result<Vec<TokenId>> {
+    let token_ids = STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if !is_custodian(&state, &caller()) {
+            return Err(Error::Unauthorized);
+        }
+        // Validate every item before minting any so the batch is atomic.
+        for meta in &metadata {
+            validate_metadata(meta)?;
+        }
+        let ids = metadata
+            .into_iter()
+            .map(|meta| mint_internal(&mut state, to, meta))
+            .collect::<Vec<_>>();
+        Ok(ids)
+    })?;
+    for token_id in &token_ids {
+        http::add_hash(token_id.clone());
+    }
+    Ok(token_ids)
+}
+
+// Validate a batch transfer up front: reject the whole batch if any token is
+// burned, missing, not owned by `from`, or not transferable by `caller`, so
+// `transferBatch` never applies a partial transfer.
+fn validate_batch_transfer(
+    state: &State,
+    caller: &Principal,
+    from: Principal,
+    to: Principal,
+    token_ids: &[TokenId],
+) -> Result<()> {
+    if to == Principal::anonymous() {
+        return Err(Error::ZeroAddress);
+    }
+    for token_id in token_ids {
+        if state.burned.contains_key(token_id) {
+            return Err(Error::TokenBurned);
+        }
+        let owner = state.tokens.get(token_id).ok_or(Error::InvalidTokenId)?;
+        if *owner != from || !is_owner_or_operator(state, caller, token_id)? {
+            return Err(Error::Unauthorized);
+        }
+    }
+    Ok(())
+}
+
+#[update(name = "transferBatchDip721")]
+fn transferBatch(from: Principal, to: Principal, token_ids: Vec<TokenId>) -> Result<()> {
     STATE.with(|s| {
         let mut state = s.borrow_mut();
-        let token_id = state.next_token_id.clone();
-        state.tokens.insert(token_id.clone(), to);
-        state.token_metadata.insert(token_id.clone(), metadata);
-        state.total_supply = state.total_supply.clone() + Nat::from(1u32);
-        state.next_token_id = state.next_token_id.clone() + Nat::from(1u32);
-        Ok(token_id)
+
+        // Validate every item before applying any so the batch is atomic.
+        validate_batch_transfer(&state, &caller(), from, to, &token_ids)?;
+
+        for token_id in token_ids {
+            state.tokens.insert(token_id.clone(), to);
+            state.token_approvals.remove(&token_id);
+            state.listings.remove(&token_id);
+            record_tx(&mut state, TxKind::Transfer { token_id, from, to });
+        }
+        Ok(())
     })
 }
 
@@ -273,6 +558,9 @@ fn tokenURI(token_id: TokenId) -> Result<String> {
 fn listItem(token_id: TokenId, price: Nat) -> Result<()> {
     STATE.with(|s| {
         let mut state = s.borrow_mut();
+        if state.burned.contains_key(&token_id) {
+            return Err(Error::TokenBurned);
+        }
         let owner = state.tokens.get(&token_id).ok_or(Error::InvalidTokenId)?;
 
         if *owner != caller() {
@@ -283,7 +571,8 @@ fn listItem(token_id: TokenId, price: Nat) -> Result<()> {
             return Err(Error::AlreadyListedForSale);
         }
 
-        state.listings.insert(token_id, Listing { seller: caller(), price });
+        state.listings.insert(token_id.clone(), Listing { seller: caller(), price: price.clone() });
+        record_tx(&mut state, TxKind::ListItem { token_id, price });
         Ok(())
     })
 }
@@ -300,6 +589,7 @@ fn delistItem(token_id: TokenId) -> Result<()> {
         }
 
         state.listings.remove(&token_id);
+        record_tx(&mut state, TxKind::DelistItem { token_id });
         Ok(())
     })
 }
@@ -315,35 +605,260 @@ fn getListing(token_id: TokenId) -> Result<Listing> {
     })
 }
 
+// -------------------- LEDGER INTEGRATION --------------------
+
+// Mainnet ICP ledger canister. Payments are settled over its ICRC-1/ICRC-2
+// endpoints so a sale moves real e8s alongside the NFT.
+const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+
+// Standard ICP ledger transfer fee in e8s. Each `icrc1_transfer` payout the
+// canister makes is charged this much on top of the transferred amount.
+const LEDGER_FEE: u64 = 10_000;
+
+#[derive(CandidType, Deserialize)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Account { owner, subaccount: None }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// ICRC-1 `icrc1_transfer` error arm. Decoded (rather than an opaque
+// `IDLValue`) so callers can tell a bad fee from a frozen account.
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// ICRC-2 `icrc2_transfer_from` error arm, which adds the allowance variant.
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+fn ledger() -> Principal {
+    Principal::from_text(ICP_LEDGER_CANISTER_ID).expect("invalid ledger principal")
+}
+
+// Pull `amount` e8s from the buyer into the canister using a pre-authorized
+// ICRC-2 allowance. Any rejection means the buyer has not funded the purchase.
+async fn pull_payment(from: Principal, amount: Nat) -> Result<()> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: from.into(),
+        to: api::id().into(),
+        amount,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let (res,): (std::result::Result<Nat, TransferFromError>,) =
+        call::call(ledger(), "icrc2_transfer_from", (args,))
+            .await
+            .map_err(|(code, msg)| Error::LedgerError(format!("{:?}: {}", code, msg)))?;
+    res.map(|_| ())
+        .map_err(|e| Error::LedgerError(format!("transfer_from: {:?}", e)))
+}
+
+// Send `amount` e8s from the canister's own balance to `to`.
+async fn payout(to: Principal, amount: Nat) -> Result<()> {
+    let args = TransferArg {
+        from_subaccount: None,
+        to: to.into(),
+        amount,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let (res,): (std::result::Result<Nat, TransferError>,) =
+        call::call(ledger(), "icrc1_transfer", (args,))
+            .await
+            .map_err(|(code, msg)| Error::LedgerError(format!("{:?}: {}", code, msg)))?;
+    res.map(|_| ())
+        .map_err(|e| Error::LedgerError(format!("transfer: {:?}", e)))
+}
+
+// Return the buyer's pulled funds after a failed settlement. `amount` is the
+// net refundable balance the caller computed (price less anything already
+// disbursed and less this transfer's own ledger fee), so the canister never
+// drains below zero. Preserves the original `cause` on success; a failed
+// refund is itself a hard error because the buyer has been debited with
+// nothing to show for it.
+async fn refund(buyer: Principal, amount: Nat, cause: Error) -> Error {
+    match payout(buyer, amount).await {
+        Ok(()) => cause,
+        Err(e) => Error::LedgerError(format!("refund failed after {:?}: {:?}", cause, e)),
+    }
+}
+
 // -------------------- BUYING LOGIC --------------------
 
+// How a sale price is divided across the royalty recipient, the seller, and
+// the ledger fees the canister pays per payout.
+struct Settlement {
+    royalty_amount: Nat,
+    seller_amount: Nat,
+    total_fee: Nat,
+}
+
+// Compute the payout split for a sale. `bps` is validated at mint so
+// `royalty_amount <= price` and the subtractions cannot trap. The ledger
+// charges a fee per payout, deducted from the seller proceeds so the canister
+// stays solvent. Rejects sales too small to cover royalty + fees.
+fn compute_settlement(price: &Nat, royalty: Option<(Principal, u16)>) -> Result<Settlement> {
+    let royalty_amount = match royalty {
+        Some((_, bps)) => price.clone() * Nat::from(bps) / Nat::from(10_000u32),
+        None => Nat::from(0u32),
+    };
+    let num_payouts = if royalty_amount > Nat::from(0u32) { 2u32 } else { 1u32 };
+    let total_fee = Nat::from(LEDGER_FEE) * Nat::from(num_payouts);
+    if *price < royalty_amount.clone() + total_fee.clone() {
+        return Err(Error::InsufficientFunds);
+    }
+    let seller_amount = price.clone() - royalty_amount.clone() - total_fee.clone();
+    Ok(Settlement { royalty_amount, seller_amount, total_fee })
+}
+
 #[update(name = "buyItem")]
-fn buyItem(token_id: TokenId) -> Result<()> {
-    STATE.with(|s| {
-        let mut state = s.borrow_mut();
-        let listing = state.listings.get(&token_id).ok_or(Error::NotListedForSale)?;
-        let buyer = caller();
-        let seller = listing.seller; // Get seller before mutable borrow
-        let token_id_clone = token_id.clone(); // Clone token_id before mutable borrow
+async fn buyItem(token_id: TokenId) -> Result<()> {
+    let buyer = caller();
 
-        if seller == buyer {
+    // Snapshot the listing and royalty terms without holding the borrow across
+    // the inter-canister awaits below.
+    let (seller, price, royalty) = STATE.with(|s| {
+        let state = s.borrow();
+        let listing = state.listings.get(&token_id).ok_or(Error::NotListedForSale)?;
+        if listing.seller == buyer {
             return Err(Error::CannotBuyOwnNFT);
         }
+        let royalty = state
+            .token_metadata
+            .get(&token_id)
+            .and_then(|meta| meta.royalty);
+        Ok((listing.seller, listing.price.clone(), royalty))
+    })?;
 
-        // -------------------- PAYMENT HANDLING (SKIPPED FOR THIS SIMULATION) --------------------
-        // In a real-world scenario, you would:
-        // 1. Check if the buyer has sufficient funds.
-        // 2. Transfer the price amount from the buyer to the seller.
-        // -----------------------------------------------------------------------------------------
+    // Split the proceeds up front (before pulling any funds): royalty to the
+    // recipient, remainder to the seller.
+    let Settlement { royalty_amount, seller_amount, .. } = compute_settlement(&price, royalty)?;
 
-        // Transfer ownership from the seller to the buyer
-        transferFromInternal(&mut state, seller, buyer, token_id_clone)?;
+    // 1. Collect the full price from the buyer into the canister.
+    pull_payment(buyer, price.clone()).await?;
 
-        // Remove the listing after successful purchase
-        state.listings.remove(&token_id);
+    // 2. Re-read the listing after the await window and re-validate it still
+    //    matches the snapshot before committing ownership. A concurrent
+    //    delist/relist (even with the seller unchanged) must not let the buyer
+    //    be charged a stale price. The funds are already pulled, so refund the
+    //    buyer — net of the refund's own ledger fee — before bailing out.
+    // Snapshot the current approval so a step-3 rollback can restore the one
+    // `transferFromInternal` is about to clear.
+    let prior_approval =
+        STATE.with(|s| s.borrow().token_approvals.get(&token_id).cloned());
+    let commit = STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        match state.listings.get(&token_id) {
+            Some(listing) if listing.seller == seller && listing.price == price => {}
+            _ => return Err(Error::NotListedForSale),
+        }
+        transferFromInternal(&mut state, seller, buyer, token_id.clone())
+    });
+    if let Err(e) = commit {
+        return Err(refund(buyer, price.clone() - Nat::from(LEDGER_FEE), e).await);
+    }
 
-        Ok(())
-    })
+    // 3. Pay out the proceeds, tracking funds that actually leave the canister
+    //    (amount plus the per-transfer ledger fee). On failure, roll back
+    //    ownership and refund only the portion still held by the canister —
+    //    never the full price, or a royalty already sent out would be double
+    //    counted and desynchronize the NFT and funds.
+    let mut disbursed = Nat::from(0u32);
+    let mut settle_err = None;
+    if royalty_amount > Nat::from(0u32) {
+        if let Some((recipient, _)) = royalty {
+            match payout(recipient, royalty_amount.clone()).await {
+                Ok(()) => disbursed += royalty_amount.clone() + Nat::from(LEDGER_FEE),
+                Err(e) => settle_err = Some(e),
+            }
+        }
+    }
+    if settle_err.is_none() {
+        match payout(seller, seller_amount.clone()).await {
+            Ok(()) => disbursed += seller_amount.clone() + Nat::from(LEDGER_FEE),
+            Err(e) => settle_err = Some(e),
+        }
+    }
+
+    if let Some(e) = settle_err {
+        // Only roll ownership back to the seller if the buyer still holds the
+        // token. IC messages interleave across the payout awaits, so the buyer
+        // may have already transferred the NFT onward; clobbering that would
+        // hand a third party's token back to the seller. If so, leave ownership
+        // untouched and surface a hard ledger error.
+        STATE.with(|s| {
+            let mut state = s.borrow_mut();
+            if state.tokens.get(&token_id) == Some(&buyer) {
+                state.tokens.insert(token_id.clone(), seller);
+                // Restore the approval cleared by `transferFromInternal`.
+                if let Some(approval) = prior_approval {
+                    state.token_approvals.insert(token_id.clone(), approval);
+                }
+            }
+        });
+        // Refund what remains in the canister after any completed payouts,
+        // net of the refund transfer's own fee, so the canister stays solvent.
+        let remaining = price.clone() - disbursed - Nat::from(LEDGER_FEE);
+        return Err(refund(buyer, remaining, e).await);
+    }
+
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        state.listings.remove(&token_id);
+        record_tx(
+            &mut state,
+            TxKind::Buy { token_id: token_id.clone(), from: seller, to: buyer, price },
+        );
+    });
+    Ok(())
 }
 
 // Internal function to handle transfer, used by buyItem
@@ -373,7 +888,7 @@ fn transferFromInternal(
 fn setName(new_name: String) -> Result<()> {
     STATE.with(|s| {
         let mut state = s.borrow_mut();
-        if caller() == state.owner.expect("REASON"){
+        if is_custodian(&state, &caller()) {
             state.name = new_name;
             Ok(())
         } else {
@@ -387,7 +902,7 @@ fn setName(new_name: String) -> Result<()> {
 fn setSymbol(new_symbol: String) -> Result<()> {
     STATE.with(|s| {
         let mut state = s.borrow_mut();
-        if caller() == state.owner.expect("REASON") {
+        if is_custodian(&state, &caller()) {
             state.symbol = new_symbol;
             Ok(())
         } else {
@@ -396,5 +911,182 @@ fn setSymbol(new_symbol: String) -> Result<()> {
     })
 }
 
+// -------------------- BURN LOGIC --------------------
+
+// Sentinel principal that holds burned tokens. The management canister
+// principal (`aaaaa-aa`) is used as an unspendable burn address.
+fn burn_address() -> Principal {
+    Principal::management_canister()
+}
+
+#[update(name = "burnDip721")]
+fn burn(token_id: TokenId) -> Result<()> {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if state.burned.contains_key(&token_id) {
+            return Err(Error::TokenBurned);
+        }
+        let owner = *state.tokens.get(&token_id).ok_or(Error::InvalidTokenId)?;
+        if !is_owner_or_operator(&state, &caller(), &token_id)? && !is_custodian(&state, &caller()) {
+            return Err(Error::Unauthorized);
+        }
+
+        // Record the prior owner so a custodian can un-burn, then move the token
+        // to the burn address and clear any active listing or approval.
+        state.burned.insert(token_id.clone(), owner);
+        state.tokens.insert(token_id.clone(), burn_address());
+        state.listings.remove(&token_id);
+        state.token_approvals.remove(&token_id);
+        record_tx(&mut state, TxKind::Burn { token_id: token_id.clone() });
+        Ok(())
+    })?;
+    // Drop the token's certified hashes so `http_request` returns 404 for it.
+    http::remove_hash(token_id);
+    Ok(())
+}
+
+#[update(name = "unburnDip721")]
+fn unburn(token_id: TokenId) -> Result<()> {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if !is_custodian(&state, &caller()) {
+            return Err(Error::Unauthorized);
+        }
+        let prior_owner = state.burned.remove(&token_id).ok_or(Error::InvalidTokenId)?;
+        state.tokens.insert(token_id.clone(), prior_owner);
+        Ok(())
+    })?;
+    // Restore the token's certified hashes so it is served over HTTP again.
+    http::add_hash(token_id);
+    Ok(())
+}
+
+// -------------------- TRANSACTION HISTORY --------------------
+
+// Narrow a `Nat` index down to a `usize` for slicing into `transactions`.
+fn nat_to_usize(n: &Nat) -> usize {
+    n.0.to_u64_digits().first().copied().unwrap_or(0) as usize
+}
+
+#[query(name = "transaction")]
+fn transaction(id: Nat) -> Result<Transaction> {
+    STATE.with(|s| {
+        s.borrow()
+            .transactions
+            .get(nat_to_usize(&id))
+            .cloned()
+            .ok_or(Error::Other(String::from("transaction not found")))
+    })
+}
+
+#[query(name = "totalTransactions")]
+fn totalTransactions() -> Nat {
+    STATE.with(|s| Nat::from(s.borrow().transactions.len() as u64))
+}
+
+#[query(name = "getTransactions")]
+fn getTransactions(start: Nat, limit: Nat) -> Vec<Transaction> {
+    STATE.with(|s| {
+        let txs = &s.borrow().transactions;
+        let start = nat_to_usize(&start).min(txs.len());
+        let end = start.saturating_add(nat_to_usize(&limit)).min(txs.len());
+        txs[start..end].to_vec()
+    })
+}
+
 // Candid boilerplate
-ic_cdk::export_candid!();
\ No newline at end of file
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    #[test]
+    fn settlement_without_royalty_deducts_one_fee() {
+        let s = compute_settlement(&Nat::from(1_000_000u32), None).unwrap();
+        assert_eq!(s.royalty_amount, Nat::from(0u32));
+        assert_eq!(s.total_fee, Nat::from(LEDGER_FEE));
+        assert_eq!(s.seller_amount, Nat::from(1_000_000u64 - LEDGER_FEE));
+    }
+
+    #[test]
+    fn settlement_with_royalty_splits_and_deducts_two_fees() {
+        // 5% royalty on 1_000_000 e8s, two payouts → two ledger fees.
+        let royalty = Some((principal(9), 500u16));
+        let s = compute_settlement(&Nat::from(1_000_000u32), royalty).unwrap();
+        assert_eq!(s.royalty_amount, Nat::from(50_000u32));
+        assert_eq!(s.total_fee, Nat::from(2 * LEDGER_FEE));
+        assert_eq!(s.seller_amount, Nat::from(1_000_000u64 - 50_000 - 2 * LEDGER_FEE));
+    }
+
+    #[test]
+    fn settlement_rejects_price_below_royalty_plus_fees() {
+        let royalty = Some((principal(9), 500u16));
+        // 20_000 e8s cannot cover the 1_000 royalty (5%) plus two 10_000 fees.
+        let err = compute_settlement(&Nat::from(20_000u32), royalty).unwrap_err();
+        assert!(matches!(err, Error::InsufficientFunds));
+    }
+
+    #[test]
+    fn settlement_exact_fee_floor_is_accepted() {
+        // Price exactly equal to the single fee leaves the seller nothing.
+        let s = compute_settlement(&Nat::from(LEDGER_FEE), None).unwrap();
+        assert_eq!(s.seller_amount, Nat::from(0u32));
+    }
+
+    fn state_with_two_tokens(owner: Principal) -> State {
+        let mut state = State::default();
+        state.tokens.insert(Nat::from(1u32), owner);
+        state.tokens.insert(Nat::from(2u32), owner);
+        state
+    }
+
+    #[test]
+    fn batch_transfer_validates_a_clean_batch() {
+        let from = principal(1);
+        let to = principal(2);
+        let state = state_with_two_tokens(from);
+        let ids = vec![Nat::from(1u32), Nat::from(2u32)];
+        assert!(validate_batch_transfer(&state, &from, from, to, &ids).is_ok());
+    }
+
+    #[test]
+    fn batch_transfer_rejects_whole_batch_if_one_token_missing() {
+        let from = principal(1);
+        let to = principal(2);
+        let state = state_with_two_tokens(from);
+        // Token 3 does not exist; the batch must be rejected without applying
+        // the valid tokens 1 and 2.
+        let ids = vec![Nat::from(1u32), Nat::from(3u32)];
+        let err = validate_batch_transfer(&state, &from, from, to, &ids).unwrap_err();
+        assert!(matches!(err, Error::InvalidTokenId));
+        // State is untouched: the function only validates, never mutates.
+        assert_eq!(state.tokens.get(&Nat::from(1u32)), Some(&from));
+    }
+
+    #[test]
+    fn batch_transfer_rejects_when_caller_is_not_owner() {
+        let from = principal(1);
+        let to = principal(2);
+        let stranger = principal(3);
+        let state = state_with_two_tokens(from);
+        let ids = vec![Nat::from(1u32)];
+        let err = validate_batch_transfer(&state, &stranger, from, to, &ids).unwrap_err();
+        assert!(matches!(err, Error::Unauthorized));
+    }
+
+    #[test]
+    fn batch_transfer_rejects_burned_token() {
+        let from = principal(1);
+        let to = principal(2);
+        let mut state = state_with_two_tokens(from);
+        state.burned.insert(Nat::from(1u32), from);
+        let ids = vec![Nat::from(1u32)];
+        let err = validate_batch_transfer(&state, &from, from, to, &ids).unwrap_err();
+        assert!(matches!(err, Error::TokenBurned));
+    }
+}
\ No newline at end of file